@@ -5,9 +5,13 @@
 
 use std::any::Any;
 use std::io::Error;
-use std::sync::Arc;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::timerfd::TimerFd;
 
 use crate::{InterruptIndex, InterruptSourceGroup, InterruptStatusRegister32};
 
@@ -31,6 +35,14 @@ pub trait InterruptNotifier: Send + Sync {
     fn as_any(&self) -> &dyn Any;
 }
 
+/// Trait to query the number of interrupts injected by a notifier.
+pub trait InterruptNotifierCounter {
+    /// Get the number of interrupts successfully injected to the virtual machine.
+    ///
+    /// The counter is shared by all clones of the notifier.
+    fn count(&self) -> u64;
+}
+
 #[cfg(feature = "legacy-irq")]
 mod legacy {
     use super::*;
@@ -41,6 +53,7 @@ mod legacy {
         pub(crate) intr_group: Arc<Box<dyn InterruptSourceGroup>>,
         pub(crate) intr_status: Arc<InterruptStatusRegister32>,
         pub(crate) status_bits: u32,
+        pub(crate) count: Arc<AtomicU64>,
     }
 
     impl LegacyNotifier {
@@ -54,6 +67,7 @@ mod legacy {
                 intr_group,
                 intr_status,
                 status_bits,
+                count: Arc::new(AtomicU64::new(0)),
             }
         }
     }
@@ -61,7 +75,9 @@ mod legacy {
     impl InterruptNotifier for LegacyNotifier {
         fn notify(&self) -> Result<(), Error> {
             self.intr_status.set_bits(self.status_bits);
-            self.intr_group.trigger(0)
+            self.intr_group.trigger(0)?;
+            self.count.fetch_add(1, Ordering::Relaxed);
+            Ok(())
         }
 
         fn notifier(&self) -> Option<&EventFd> {
@@ -76,6 +92,12 @@ mod legacy {
             self
         }
     }
+
+    impl InterruptNotifierCounter for LegacyNotifier {
+        fn count(&self) -> u64 {
+            self.count.load(Ordering::Relaxed)
+        }
+    }
 }
 
 #[cfg(feature = "msi-irq")]
@@ -87,6 +109,7 @@ mod msi {
     pub struct MsiNotifier {
         pub(crate) intr_group: Arc<Box<dyn InterruptSourceGroup>>,
         pub(crate) intr_index: InterruptIndex,
+        pub(crate) count: Arc<AtomicU64>,
     }
 
     impl MsiNotifier {
@@ -98,13 +121,16 @@ mod msi {
             MsiNotifier {
                 intr_group,
                 intr_index,
+                count: Arc::new(AtomicU64::new(0)),
             }
         }
     }
 
     impl InterruptNotifier for MsiNotifier {
         fn notify(&self) -> Result<(), Error> {
-            self.intr_group.trigger(self.intr_index)
+            self.intr_group.trigger(self.intr_index)?;
+            self.count.fetch_add(1, Ordering::Relaxed);
+            Ok(())
         }
 
         fn notifier(&self) -> Option<&EventFd> {
@@ -119,6 +145,12 @@ mod msi {
             self
         }
     }
+
+    impl InterruptNotifierCounter for MsiNotifier {
+        fn count(&self) -> u64 {
+            self.count.load(Ordering::Relaxed)
+        }
+    }
 }
 
 /// Struct to discard interrupts.
@@ -150,6 +182,169 @@ impl InterruptNotifier for NoopNotifier {
     }
 }
 
+/// Configuration information to coalesce interrupts.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CoalescingConfig {
+    /// Maximum time to hold back an interrupt before delivering it to the guest.
+    pub max_delay: Duration,
+    /// Maximum number of signals folded into one interrupt, zero means no limit.
+    pub max_count: u32,
+}
+
+struct CoalescingState {
+    timer: TimerFd,
+    window_open: bool,
+    pending: u32,
+}
+
+/// Struct to coalesce interrupts injected by another notifier.
+///
+/// The first signal is delivered immediately and opens a coalescing window. Further signals
+/// received within the window are folded into one interrupt, which is delivered once `max_count`
+/// signals have been accumulated or when the window expires. The window is closed when it
+/// expires without any pending signal.
+///
+/// The owner must poll the file descriptor returned by `timer_fd()` and call `handle_timer()`
+/// when it becomes readable, otherwise the last coalesced interrupt will never be delivered.
+///
+/// `notifier()` returns the `EventFd` of the wrapped notifier, so interrupts injected by writing
+/// to it directly, for example from a vhost backend, bypass coalescing.
+#[derive(Clone)]
+pub struct CoalescingNotifier {
+    inner: Arc<dyn InterruptNotifier>,
+    config: CoalescingConfig,
+    state: Arc<Mutex<CoalescingState>>,
+    timer_fd: RawFd,
+    count: Arc<AtomicU64>,
+}
+
+impl CoalescingNotifier {
+    /// Create a notifier to coalesce interrupts injected by `inner`.
+    pub fn new(inner: Arc<dyn InterruptNotifier>, config: CoalescingConfig) -> Result<Self, Error> {
+        // A zero duration disarms the timer, so the window would never be closed.
+        if config.max_delay.is_zero() {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let mut timer = TimerFd::new().map_err(|e| Error::from_raw_os_error(e.errno()))?;
+        // Make sure `max_delay` is accepted by the timer, it fails later otherwise.
+        timer
+            .reset(config.max_delay, None)
+            .and_then(|_| timer.clear())
+            .map_err(|e| Error::from_raw_os_error(e.errno()))?;
+        let timer_fd = timer.as_raw_fd();
+        // Safe because we own the file descriptor and only query its status flags.
+        let flags = unsafe { libc::fcntl(timer_fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(Error::last_os_error());
+        }
+        // Safe because we own the file descriptor and only change its status flags.
+        let ret = unsafe { libc::fcntl(timer_fd, libc::F_SETFL, flags | libc::O_NONBLOCK) };
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(CoalescingNotifier {
+            inner,
+            config,
+            state: Arc::new(Mutex::new(CoalescingState {
+                timer,
+                window_open: false,
+                pending: 0,
+            })),
+            timer_fd,
+            count: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Get the file descriptor of the timer which closes coalescing windows.
+    ///
+    /// The file descriptor is non-blocking and becomes readable when the window expires.
+    pub fn timer_fd(&self) -> RawFd {
+        self.timer_fd
+    }
+
+    /// Handle expiration of the coalescing window.
+    ///
+    /// Deliver the pending interrupt if any and start a new window, otherwise close the window.
+    pub fn handle_timer(&self) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        match state.timer.wait() {
+            Ok(_) => {}
+            Err(e) if e.errno() == libc::EAGAIN => return Ok(()),
+            Err(e) => return Err(Error::from_raw_os_error(e.errno())),
+        }
+
+        if state.pending > 0 {
+            // Re-arm the timer before delivering so a failed delivery is retried on next expiry.
+            if Self::arm_timer(&mut state, self.config.max_delay).is_err() {
+                // Close the window so that the next signal is delivered immediately.
+                state.window_open = false;
+                state.pending = 0;
+                return self.deliver();
+            }
+            self.deliver()?;
+            state.pending = 0;
+            Ok(())
+        } else {
+            state.window_open = false;
+            Ok(())
+        }
+    }
+
+    fn deliver(&self) -> Result<(), Error> {
+        self.inner.notify()?;
+        self.count.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn arm_timer(state: &mut CoalescingState, delay: Duration) -> Result<(), Error> {
+        state
+            .timer
+            .reset(delay, None)
+            .map_err(|e| Error::from_raw_os_error(e.errno()))
+    }
+}
+
+impl InterruptNotifier for CoalescingNotifier {
+    fn notify(&self) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        if !state.window_open {
+            // Arm the timer first so that an error always means the interrupt isn't delivered.
+            Self::arm_timer(&mut state, self.config.max_delay)?;
+            self.deliver()?;
+            state.window_open = true;
+            return Ok(());
+        }
+
+        state.pending = state.pending.saturating_add(1);
+        if self.config.max_count != 0 && state.pending >= self.config.max_count {
+            self.deliver()?;
+            state.pending = 0;
+        }
+        Ok(())
+    }
+
+    fn notifier(&self) -> Option<&EventFd> {
+        self.inner.notifier()
+    }
+
+    fn clone_boxed(&self) -> Box<dyn InterruptNotifier> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Count interrupts delivered through the wrapped notifier, coalesced signals are not counted.
+impl InterruptNotifierCounter for CoalescingNotifier {
+    fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
 /// Clone a boxed interrupt notifier object.
 pub fn clone_notifier(notifier: &dyn InterruptNotifier) -> Box<dyn InterruptNotifier> {
     notifier.clone_boxed()
@@ -161,11 +356,152 @@ mod tests {
     #![allow(dead_code)]
     use super::*;
 
+    use std::sync::atomic::AtomicBool;
+
     use crate::{InterruptManager, InterruptSourceType};
 
     const VIRTIO_INTR_VRING: u32 = 0x01;
     const VIRTIO_INTR_CONFIG: u32 = 0x02;
 
+    #[derive(Clone, Default)]
+    struct MockNotifier {
+        count: Arc<AtomicU64>,
+        fail_once: Arc<AtomicBool>,
+    }
+
+    impl MockNotifier {
+        fn count(&self) -> u64 {
+            self.count.load(Ordering::SeqCst)
+        }
+    }
+
+    impl InterruptNotifier for MockNotifier {
+        fn notify(&self) -> Result<(), Error> {
+            if self.fail_once.swap(false, Ordering::SeqCst) {
+                return Err(Error::from_raw_os_error(libc::EIO));
+            }
+            self.count.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn notifier(&self) -> Option<&EventFd> {
+            None
+        }
+
+        fn clone_boxed(&self) -> Box<dyn InterruptNotifier> {
+            Box::new(self.clone())
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    // Wait until the coalescing window expires, instead of sleeping for a fixed period.
+    fn wait_for_timer(notifier: &CoalescingNotifier) {
+        let mut pollfd = libc::pollfd {
+            fd: notifier.timer_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Safe because we pass a single valid pollfd and check the return value.
+        let ret = unsafe { libc::poll(&mut pollfd, 1, 5000) };
+        assert_eq!(ret, 1);
+    }
+
+    fn create_coalescing_notifier(
+        max_delay: Duration,
+        max_count: u32,
+    ) -> (MockNotifier, CoalescingNotifier) {
+        let inner = MockNotifier::default();
+        let config = CoalescingConfig {
+            max_delay,
+            max_count,
+        };
+        let notifier = CoalescingNotifier::new(Arc::new(inner.clone()), config).unwrap();
+        (inner, notifier)
+    }
+
+    #[test]
+    fn test_coalescing_notifier_window() {
+        let config = CoalescingConfig {
+            max_delay: Duration::from_millis(0),
+            max_count: 0,
+        };
+        assert!(CoalescingNotifier::new(Arc::new(MockNotifier::default()), config).is_err());
+        let config = CoalescingConfig {
+            max_delay: Duration::from_secs(u64::MAX),
+            max_count: 0,
+        };
+        assert!(CoalescingNotifier::new(Arc::new(MockNotifier::default()), config).is_err());
+
+        let (inner, notifier) = create_coalescing_notifier(Duration::from_millis(1), 0);
+        assert!(notifier.notifier().is_none());
+
+        // Spurious timer event before any window is opened.
+        notifier.handle_timer().unwrap();
+        assert_eq!(inner.count(), 0);
+
+        for _ in 0..10 {
+            notifier.notify().unwrap();
+        }
+        assert_eq!(inner.count(), 1);
+
+        // The last coalesced interrupt is delivered when the window expires.
+        wait_for_timer(&notifier);
+        notifier.handle_timer().unwrap();
+        assert_eq!(inner.count(), 2);
+
+        // The window is closed once it expires without pending signals.
+        wait_for_timer(&notifier);
+        notifier.handle_timer().unwrap();
+        assert_eq!(inner.count(), 2);
+        notifier.notify().unwrap();
+        assert_eq!(inner.count(), 3);
+    }
+
+    #[test]
+    fn test_coalescing_notifier_count() {
+        let (inner, notifier) = create_coalescing_notifier(Duration::from_secs(60), 4);
+
+        // One immediate delivery, then one for every four coalesced signals.
+        for _ in 0..9 {
+            notifier.notify().unwrap();
+        }
+        assert_eq!(inner.count(), 3);
+        assert_eq!(InterruptNotifierCounter::count(&notifier), 3);
+    }
+
+    #[test]
+    fn test_coalescing_notifier_retry() {
+        let (inner, notifier) = create_coalescing_notifier(Duration::from_millis(1), 0);
+
+        notifier.notify().unwrap();
+        notifier.notify().unwrap();
+        assert_eq!(inner.count(), 1);
+
+        // Delivery of the coalesced interrupt fails, it must be retried on next expiry.
+        inner.fail_once.store(true, Ordering::SeqCst);
+        wait_for_timer(&notifier);
+        assert!(notifier.handle_timer().is_err());
+        assert_eq!(inner.count(), 1);
+
+        notifier.notify().unwrap();
+        wait_for_timer(&notifier);
+        notifier.handle_timer().unwrap();
+        assert_eq!(inner.count(), 2);
+
+        // A failed first delivery doesn't open the window.
+        wait_for_timer(&notifier);
+        notifier.handle_timer().unwrap();
+        inner.fail_once.store(true, Ordering::SeqCst);
+        assert!(notifier.notify().is_err());
+        notifier.notify().unwrap();
+        assert_eq!(inner.count(), 3);
+        // Failed deliveries are not counted.
+        assert_eq!(InterruptNotifierCounter::count(&notifier), 3);
+    }
+
     #[test]
     fn create_virtio_null_notifier() {
         let notifier = NoopNotifier::new();
@@ -204,6 +540,28 @@ mod tests {
         assert_eq!(clone.as_any().type_id(), notifier.as_any().type_id());
     }
 
+    #[cfg(feature = "kvm-legacy-irq")]
+    #[test]
+    fn test_legacy_notifier_counter() {
+        let (_vmfd, irq_manager) = crate::kvm::tests::create_kvm_irq_manager();
+        let group = irq_manager
+            .create_group(InterruptSourceType::LegacyIrq, 0, 1)
+            .unwrap();
+        let status = Arc::new(InterruptStatusRegister32::new());
+
+        let notifier = LegacyNotifier::new(group, status, VIRTIO_INTR_VRING);
+        assert_eq!(notifier.count(), 0);
+        for _ in 0..5 {
+            notifier.notify().unwrap();
+        }
+        assert_eq!(notifier.count(), 5);
+
+        let clone = notifier.clone();
+        clone.notify().unwrap();
+        assert_eq!(clone.count(), 6);
+        assert_eq!(notifier.count(), 6);
+    }
+
     #[cfg(feature = "kvm-msi-irq")]
     #[test]
     fn test_virtio_msi_notifier() {
@@ -227,4 +585,55 @@ mod tests {
         let clone = clone_notifier(&notifier1);
         assert_eq!(clone.as_any().type_id(), notifier1.as_any().type_id());
     }
+
+    #[cfg(feature = "kvm-msi-irq")]
+    #[test]
+    fn test_msi_notifier_counter() {
+        let (_vmfd, irq_manager) = crate::kvm::tests::create_kvm_irq_manager();
+        let group = irq_manager
+            .create_group(InterruptSourceType::MsiIrq, 0, 2)
+            .unwrap();
+        let notifier1 = MsiNotifier::new(group.clone(), 0);
+        let notifier2 = MsiNotifier::new(group, 1);
+
+        for _ in 0..3 {
+            notifier1.notify().unwrap();
+        }
+        notifier2.notify().unwrap();
+        assert_eq!(notifier1.count(), 3);
+        assert_eq!(notifier2.count(), 1);
+
+        let clone = notifier1.clone();
+        clone.notify().unwrap();
+        assert_eq!(notifier1.count(), 4);
+    }
+
+    #[cfg(feature = "kvm-msi-irq")]
+    #[test]
+    fn test_coalescing_msi_notifier() {
+        let (_vmfd, irq_manager) = crate::kvm::tests::create_kvm_irq_manager();
+        let group = irq_manager
+            .create_group(InterruptSourceType::MsiIrq, 0, 1)
+            .unwrap();
+        let inner = Arc::new(MsiNotifier::new(group, 0));
+        let eventfd = inner.notifier().unwrap().try_clone().unwrap();
+
+        let config = CoalescingConfig {
+            max_delay: Duration::from_secs(60),
+            max_count: 4,
+        };
+        let notifier = CoalescingNotifier::new(inner.clone(), config).unwrap();
+        assert!(notifier.notifier().is_some());
+
+        // One immediate delivery, then one for every four coalesced signals.
+        for _ in 0..9 {
+            notifier.notify().unwrap();
+        }
+        assert_eq!(eventfd.read().unwrap(), 3);
+        assert_eq!(inner.count(), 3);
+        assert_eq!(notifier.count(), 3);
+
+        let clone = clone_notifier(&notifier);
+        assert_eq!(clone.as_any().type_id(), notifier.as_any().type_id());
+    }
 }
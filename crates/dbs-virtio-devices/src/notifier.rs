@@ -6,11 +6,11 @@
 use std::sync::Arc;
 
 use dbs_interrupt::{
-    InterruptIndex, InterruptNotifier, InterruptSourceGroup, InterruptSourceType,
-    InterruptStatusRegister32, LegacyNotifier, MsiNotifier,
+    CoalescingConfig, CoalescingNotifier, InterruptIndex, InterruptNotifier, InterruptSourceGroup,
+    InterruptSourceType, InterruptStatusRegister32, LegacyNotifier, MsiNotifier,
 };
 
-use crate::{VIRTIO_INTR_CONFIG, VIRTIO_INTR_VRING};
+use crate::{Error, Result, VIRTIO_INTR_CONFIG, VIRTIO_INTR_VRING};
 
 /// Create an interrupt notifier for virtio device change events.
 pub fn create_device_notifier(
@@ -27,23 +27,35 @@ pub fn create_device_notifier(
 }
 
 /// Create an interrupt notifier for virtio queue notification events.
+///
+/// If `coalescing` is specified, the notifier is wrapped by a `CoalescingNotifier` and the
+/// caller should get it back through `InterruptNotifier::as_any()` to service its timer.
 pub fn create_queue_notifier(
     group: Arc<Box<dyn InterruptSourceGroup>>,
     intr_status: Arc<InterruptStatusRegister32>,
     intr_index: InterruptIndex,
-) -> Arc<dyn InterruptNotifier> {
-    match group.interrupt_type() {
+    coalescing: Option<CoalescingConfig>,
+) -> Result<Arc<dyn InterruptNotifier>> {
+    let notifier: Arc<dyn InterruptNotifier> = match group.interrupt_type() {
         InterruptSourceType::LegacyIrq => {
             Arc::new(LegacyNotifier::new(group, intr_status, VIRTIO_INTR_VRING))
         }
         InterruptSourceType::MsiIrq => Arc::new(MsiNotifier::new(group, intr_index)),
+    };
+
+    match coalescing {
+        Some(config) => Ok(Arc::new(
+            CoalescingNotifier::new(notifier, config).map_err(Error::IOError)?,
+        )),
+        None => Ok(notifier),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use dbs_interrupt::InterruptManager;
+    use dbs_interrupt::{InterruptManager, InterruptNotifierCounter};
+    use std::time::Duration;
 
     #[test]
     fn test_create_virtio_legacy_notifier() {
@@ -54,7 +66,7 @@ mod tests {
         let status = Arc::new(InterruptStatusRegister32::new());
         assert_eq!(status.read(), 0);
 
-        let notifer = create_queue_notifier(group.clone(), status.clone(), 0);
+        let notifer = create_queue_notifier(group.clone(), status.clone(), 0, None).unwrap();
         notifer.notify().unwrap();
         assert!(notifer.notifier().is_some());
 
@@ -75,8 +87,8 @@ mod tests {
         let status = Arc::new(InterruptStatusRegister32::new());
 
         let notifier1 = create_device_notifier(group.clone(), status.clone(), 1);
-        let notifier2 = create_queue_notifier(group.clone(), status.clone(), 2);
-        let notifier3 = create_queue_notifier(group.clone(), status, 3);
+        let notifier2 = create_queue_notifier(group.clone(), status.clone(), 2, None).unwrap();
+        let notifier3 = create_queue_notifier(group.clone(), status, 3, None).unwrap();
         assert!(notifier1.notifier().is_some());
         assert!(notifier2.notifier().is_some());
         assert!(notifier3.notifier().is_none());
@@ -86,4 +98,64 @@ mod tests {
         assert_eq!(notifier1.notifier().unwrap().read().unwrap(), 2);
         assert_eq!(notifier2.notifier().unwrap().read().unwrap(), 1);
     }
+
+    #[test]
+    fn test_create_virtio_coalescing_notifier() {
+        let (_vmfd, irq_manager) = crate::tests::create_vm_and_irq_manager();
+        let config = CoalescingConfig {
+            max_delay: Duration::from_secs(60),
+            max_count: 4,
+        };
+
+        let group = irq_manager
+            .create_group(InterruptSourceType::MsiIrq, 0, 1)
+            .unwrap();
+        let status = Arc::new(InterruptStatusRegister32::new());
+        assert!(create_queue_notifier(
+            group.clone(),
+            status.clone(),
+            0,
+            Some(CoalescingConfig {
+                max_delay: Duration::from_millis(0),
+                max_count: 0,
+            }),
+        )
+        .is_err());
+
+        let notifier = create_queue_notifier(group, status, 0, Some(config)).unwrap();
+        let coalescing = notifier
+            .as_any()
+            .downcast_ref::<CoalescingNotifier>()
+            .unwrap();
+        for _ in 0..9 {
+            notifier.notify().unwrap();
+        }
+        assert_eq!(coalescing.count(), 3);
+        assert_eq!(notifier.notifier().unwrap().read().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_create_virtio_legacy_coalescing_notifier() {
+        let (_vmfd, irq_manager) = crate::tests::create_vm_and_irq_manager();
+        let config = CoalescingConfig {
+            max_delay: Duration::from_secs(60),
+            max_count: 4,
+        };
+
+        let group = irq_manager
+            .create_group(InterruptSourceType::LegacyIrq, 0, 1)
+            .unwrap();
+        let status = Arc::new(InterruptStatusRegister32::new());
+        let notifier = create_queue_notifier(group, status.clone(), 0, Some(config)).unwrap();
+        let coalescing = notifier
+            .as_any()
+            .downcast_ref::<CoalescingNotifier>()
+            .unwrap();
+        for _ in 0..9 {
+            notifier.notify().unwrap();
+        }
+        assert_eq!(coalescing.count(), 3);
+        assert_eq!(status.read(), VIRTIO_INTR_VRING);
+        assert_eq!(notifier.notifier().unwrap().read().unwrap(), 3);
+    }
 }